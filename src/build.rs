@@ -1,15 +1,83 @@
 use crate::get_default_target;
+use crate::pgo::rustc_wrapper::{WRAPPER_FLAGS_ENV, WRAPPER_WORKSPACE_ROOT_ENV};
 use crate::pgo::CargoCommand;
+use cargo_metadata::camino::Utf8PathBuf;
 use cargo_metadata::Message;
 use colored::Colorize;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Write;
 use std::process::{Command, Output};
 
+/// The user-facing output format for the build, mirroring cargo's own
+/// `--message-format`. cargo-pgo always asks cargo for a JSON envelope so it can
+/// parse artifacts and diagnostics internally, then renders or forwards the
+/// stream in whichever format the user requested.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum MessageFormat {
+    /// Human-readable rendered diagnostics (the default).
+    #[default]
+    Human,
+    /// Short, one-line rendered diagnostics.
+    Short,
+    /// The raw cargo JSON stream, diagnostics unrendered.
+    Json,
+    /// The cargo JSON stream with rendered diagnostics embedded.
+    JsonRenderDiagnostics,
+}
+
+impl MessageFormat {
+    /// The `--message-format` cargo-pgo passes to cargo. Every variant is a JSON
+    /// envelope so [`handle_metadata_message`] can always parse the stream; the
+    /// diagnostic rendering differs to match what the user asked for.
+    fn cargo_arg(self) -> &'static str {
+        match self {
+            MessageFormat::Human => "json-diagnostic-rendered-ansi",
+            MessageFormat::Short => "json-diagnostic-short",
+            MessageFormat::Json => "json",
+            MessageFormat::JsonRenderDiagnostics => "json-render-diagnostics",
+        }
+    }
+
+    /// Whether the message stream should be forwarded as JSON rather than
+    /// rendered for humans.
+    fn is_json(self) -> bool {
+        matches!(
+            self,
+            MessageFormat::Json | MessageFormat::JsonRenderDiagnostics
+        )
+    }
+
+    /// The literal `--message-format` value the user asked for. Unlike
+    /// [`MessageFormat::cargo_arg`], which always forces a JSON envelope so
+    /// cargo-pgo can parse the stream itself, this is what `show-env` should
+    /// export: a user driving their own `cargo build` wants plain `human`
+    /// output, not cargo-pgo's internal JSON wrapping of it.
+    fn user_arg(self) -> &'static str {
+        match self {
+            MessageFormat::Human => "human",
+            MessageFormat::Short => "short",
+            MessageFormat::Json => "json",
+            MessageFormat::JsonRenderDiagnostics => "json-render-diagnostics",
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 struct CargoArgs {
     filtered: Vec<String>,
-    contains_target: bool,
+    target: Option<String>,
+}
+
+/// Resolve the host's default target triple, wrapping the error with the
+/// message used everywhere cargo-pgo needs a `--target` to instrument or
+/// locate sysroot tools.
+pub(crate) fn resolve_default_target() -> anyhow::Result<String> {
+    get_default_target().map_err(|error| {
+        anyhow::anyhow!(
+            "Unable to find default target triple for your platform: {:?}",
+            error
+        )
+    })
 }
 
 /// Run `cargo` command in release mode with the provided RUSTFLAGS and Cargo arguments.
@@ -18,13 +86,36 @@ pub fn cargo_command_with_flags(
     flags: &str,
     cargo_args: Vec<String>,
 ) -> anyhow::Result<Output> {
-    let mut rustflags = std::env::var("RUSTFLAGS").unwrap_or_default();
-    write!(&mut rustflags, " {}", flags).unwrap();
+    cargo_command_with_flags_mode(
+        command,
+        flags,
+        cargo_args,
+        Instrumentation::Rustflags,
+        MessageFormat::default(),
+    )
+}
 
-    let mut env = HashMap::default();
-    env.insert("RUSTFLAGS".to_string(), rustflags);
+/// Selects how the instrumentation flags reach the compiler.
+#[derive(Debug, Copy, Clone)]
+pub enum Instrumentation {
+    /// Append the flags to a global `RUSTFLAGS`, instrumenting the whole graph.
+    Rustflags,
+    /// Set a `RUSTC_WRAPPER` shim that only instruments workspace members.
+    Selective,
+}
 
-    let output = cargo_command(command, cargo_args, env)?;
+/// Run `cargo` command in release mode, applying `flags` either globally via
+/// `RUSTFLAGS` or selectively through a `RUSTC_WRAPPER` shim.
+pub fn cargo_command_with_flags_mode(
+    command: CargoCommand,
+    flags: &str,
+    cargo_args: Vec<String>,
+    instrumentation: Instrumentation,
+    message_format: MessageFormat,
+) -> anyhow::Result<Output> {
+    let env = instrumentation_env(flags, instrumentation)?;
+
+    let output = cargo_command(command, cargo_args, env, message_format)?;
     if !output.status.success() {
         Err(anyhow::anyhow!(
             "Cargo error ({}): {}",
@@ -36,11 +127,95 @@ pub fn cargo_command_with_flags(
     }
 }
 
+/// Assemble the environment variables cargo-pgo sets for an instrumentation
+/// phase. This is the single source of truth shared by the real cargo
+/// invocation and by `show-env`, so both agree on exactly what is exported.
+pub fn instrumentation_env(
+    flags: &str,
+    instrumentation: Instrumentation,
+) -> anyhow::Result<HashMap<String, String>> {
+    let mut env = HashMap::default();
+    match instrumentation {
+        Instrumentation::Rustflags => {
+            let mut rustflags = std::env::var("RUSTFLAGS").unwrap_or_default();
+            write!(&mut rustflags, " {}", flags).unwrap();
+            env.insert("RUSTFLAGS".to_string(), rustflags);
+        }
+        Instrumentation::Selective => {
+            // The shim is cargo-pgo itself; Cargo re-invokes us per crate and we
+            // forward to the real rustc, instrumenting only workspace members.
+            let wrapper = std::env::current_exe()?;
+            let workspace_root = std::env::current_dir()?;
+
+            env.insert(
+                "RUSTC_WRAPPER".to_string(),
+                wrapper.to_string_lossy().into_owned(),
+            );
+            env.insert(WRAPPER_FLAGS_ENV.to_string(), flags.to_string());
+            env.insert(
+                WRAPPER_WORKSPACE_ROOT_ENV.to_string(),
+                workspace_root.to_string_lossy().into_owned(),
+            );
+        }
+    }
+    Ok(env)
+}
+
+/// Print the environment cargo-pgo would use for a phase without building
+/// anything, so users with bespoke pipelines can `eval $(cargo pgo show-env …)`
+/// and drive the compile/run/merge steps themselves.
+///
+/// `cargo_args` is inspected only for a user-supplied `--target`, which is
+/// honored instead of the host default. `message_format` is exported
+/// verbatim as the literal value the user asked for (see
+/// [`MessageFormat::user_arg`]) since the caller is driving `cargo` directly,
+/// not going through cargo-pgo's own JSON parsing.
+///
+/// With `json` the variables (plus the resolved `--target` and message format)
+/// are emitted as a single object; otherwise they are printed as
+/// shell-evalable `export KEY='VALUE'` lines.
+pub fn show_env(
+    flags: &str,
+    instrumentation: Instrumentation,
+    cargo_args: Vec<String>,
+    message_format: MessageFormat,
+    json: bool,
+) -> anyhow::Result<()> {
+    let mut env = instrumentation_env(flags, instrumentation)?;
+
+    let parsed_args = parse_cargo_args(cargo_args);
+    let target = match parsed_args.target {
+        Some(target) => target,
+        None => resolve_default_target()?,
+    };
+    env.insert("CARGO_BUILD_TARGET".to_string(), target);
+    env.insert(
+        "CARGO_BUILD_MESSAGE_FORMAT".to_string(),
+        message_format.user_arg().to_string(),
+    );
+
+    // Sort for stable, reproducible output.
+    let entries: BTreeMap<String, String> = env.into_iter().collect();
+
+    if json {
+        println!("{}", serde_json::to_string(&entries)?);
+    } else {
+        for (key, value) in entries {
+            // Single-quote the value so `eval` doesn't expand `$`, backticks or
+            // backslashes inside it; close, escape, reopen the quote for any
+            // literal single quote the value itself contains.
+            println!("export {}='{}'", key, value.replace('\'', "'\\''"));
+        }
+    }
+    Ok(())
+}
+
 /// Run `cargo` command in release mode with the provided env variables and Cargo arguments.
 fn cargo_command(
     cargo_cmd: CargoCommand,
     cargo_args: Vec<String>,
     env: HashMap<String, String>,
+    message_format: MessageFormat,
 ) -> anyhow::Result<Output> {
     let parsed_args = parse_cargo_args(cargo_args);
 
@@ -49,19 +224,13 @@ fn cargo_command(
         cargo_cmd.to_str(),
         "--release",
         "--message-format",
-        "json-diagnostic-rendered-ansi",
+        message_format.cargo_arg(),
     ]);
 
     // --target is passed to avoid instrumenting build scripts
     // See https://doc.rust-lang.org/rustc/profile-guided-optimization.html#a-complete-cargo-workflow
-    if !parsed_args.contains_target {
-        let default_target = get_default_target().map_err(|error| {
-            anyhow::anyhow!(
-                "Unable to find default target triple for your platform: {:?}",
-                error
-            )
-        })?;
-        command.args(&["--target", &default_target]);
+    if parsed_args.target.is_none() {
+        command.args(&["--target", &resolve_default_target()?]);
     }
 
     for arg in parsed_args.filtered {
@@ -90,8 +259,13 @@ fn parse_cargo_args(cargo_args: Vec<String>) -> CargoArgs {
                 iterator.next(); // skip flag value
             }
             "--target" => {
-                args.contains_target = true;
-                args.filtered.push(arg);
+                if let Some(value) = iterator.next() {
+                    args.target = Some(value.clone());
+                    args.filtered.push(arg);
+                    args.filtered.push(value);
+                } else {
+                    args.filtered.push(arg);
+                }
             }
             _ => args.filtered.push(arg),
         }
@@ -99,21 +273,82 @@ fn parse_cargo_args(cargo_args: Vec<String>) -> CargoArgs {
     args
 }
 
-pub fn handle_metadata_message(message: Message) {
-    match message {
-        Message::TextLine(line) => {
-            log::debug!("TextLine {}", line);
-            println!("{}", line)
+/// An executable produced by a cargo build. Knowing which executables were
+/// built lets `cargo pgo build` report exactly what to profile in a
+/// multi-target workspace instead of the caller having to guess the path under
+/// `target/<triple>/release/`.
+#[derive(Debug, Clone)]
+pub struct BuiltExecutable {
+    pub path: Utf8PathBuf,
+    pub kind: ExecutableKind,
+}
+
+/// Distinguishes a regular binary from a test harness, since the two are driven
+/// differently when gathering profiles.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExecutableKind {
+    /// A `[[bin]]` (or example) target.
+    Binary,
+    /// A test harness produced by `cargo test`.
+    Test,
+}
+
+/// Forward one line of cargo's metadata stream to the user in the requested
+/// `message_format` and, when it announces a freshly built executable, return
+/// it so the build phase can collect every instrumented binary it produced.
+///
+/// `line` is parsed to inspect it for an artifact and, for a human format, to
+/// render it; a JSON format forwards `line` itself byte-for-byte rather than
+/// re-serializing the parsed [`Message`], so a downstream tool that already
+/// parses cargo's JSON protocol sees exactly the stream cargo produced.
+pub fn handle_metadata_message(
+    line: &str,
+    message_format: MessageFormat,
+) -> Option<BuiltExecutable> {
+    let message: Message = match serde_json::from_str(line) {
+        Ok(message) => message,
+        Err(error) => {
+            log::warn!("Could not parse cargo message line: {}", error);
+            if message_format.is_json() {
+                println!("{}", line);
+            }
+            return None;
         }
-        Message::CompilerMessage(message) => {
-            log::debug!("CompilerMessage {}", message);
-            print!(
-                "{}",
-                message.message.rendered.unwrap_or(message.message.message)
-            )
+    };
+
+    let executable = match &message {
+        Message::CompilerArtifact(artifact) => artifact.executable.clone().map(|path| {
+            let kind = if artifact.profile.test {
+                ExecutableKind::Test
+            } else {
+                ExecutableKind::Binary
+            };
+            log::debug!("CompilerArtifact {} ({:?})", path, kind);
+            BuiltExecutable { path, kind }
+        }),
+        _ => None,
+    };
+
+    if message_format.is_json() {
+        println!("{}", line);
+    } else {
+        match message {
+            Message::TextLine(line) => {
+                log::debug!("TextLine {}", line);
+                println!("{}", line);
+            }
+            Message::CompilerMessage(message) => {
+                log::debug!("CompilerMessage {}", message);
+                print!(
+                    "{}",
+                    message.message.rendered.unwrap_or(message.message.message)
+                );
+            }
+            _ => {}
         }
-        _ => {}
     }
+
+    executable
 }
 
 #[cfg(test)]
@@ -152,6 +387,45 @@ mod tests {
             args.filtered,
             vec!["--target".to_string(), "x64".to_string(), "bar".to_string()]
         );
-        assert!(args.contains_target);
+        assert_eq!(args.target, Some("x64".to_string()));
+    }
+
+    #[test]
+    fn test_message_format_cargo_arg_always_requests_json() {
+        use crate::build::MessageFormat;
+
+        assert_eq!(
+            MessageFormat::Human.cargo_arg(),
+            "json-diagnostic-rendered-ansi"
+        );
+        assert_eq!(MessageFormat::Short.cargo_arg(), "json-diagnostic-short");
+        assert_eq!(MessageFormat::Json.cargo_arg(), "json");
+        assert_eq!(
+            MessageFormat::JsonRenderDiagnostics.cargo_arg(),
+            "json-render-diagnostics"
+        );
+    }
+
+    #[test]
+    fn test_message_format_is_json() {
+        use crate::build::MessageFormat;
+
+        assert!(!MessageFormat::Human.is_json());
+        assert!(!MessageFormat::Short.is_json());
+        assert!(MessageFormat::Json.is_json());
+        assert!(MessageFormat::JsonRenderDiagnostics.is_json());
+    }
+
+    #[test]
+    fn test_message_format_user_arg_matches_what_the_user_asked_for() {
+        use crate::build::MessageFormat;
+
+        assert_eq!(MessageFormat::Human.user_arg(), "human");
+        assert_eq!(MessageFormat::Short.user_arg(), "short");
+        assert_eq!(MessageFormat::Json.user_arg(), "json");
+        assert_eq!(
+            MessageFormat::JsonRenderDiagnostics.user_arg(),
+            "json-render-diagnostics"
+        );
     }
 }
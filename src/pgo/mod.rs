@@ -1,8 +1,11 @@
 use colored::Colorize;
+use std::path::PathBuf;
+use std::process::Command;
 
 pub(crate) mod env;
 pub mod instrument;
 pub mod optimize;
+pub(crate) mod rustc_wrapper;
 
 pub fn llvm_profdata_install_hint() -> String {
     format!(
@@ -12,6 +15,79 @@ add its `bin` directory to PATH.",
     )
 }
 
+/// Resolve an LLVM tool (e.g. `llvm-profdata`) shipped by the
+/// `llvm-tools-preview` component.
+///
+/// The component installs these binaries into the rustc sysroot rather than
+/// onto `PATH`, so we first look under
+/// `<sysroot>/lib/rustlib/<target>/bin/<tool>` (the same location cargo-binutils
+/// uses) and only fall back to `PATH` if they are not found there. The install
+/// hint is surfaced only when both lookups fail.
+pub fn find_llvm_tool(tool: &str) -> anyhow::Result<PathBuf> {
+    let file_name = tool_file_name(tool);
+
+    if let Some(candidate) = sysroot_tool_candidate(&file_name) {
+        if candidate.is_file() {
+            return Ok(candidate);
+        }
+    }
+
+    if let Some(path) = search_path(&file_name) {
+        return Ok(path);
+    }
+
+    Err(anyhow::anyhow!(
+        "Could not find `{}` in the rustc sysroot or on PATH. {}",
+        tool,
+        llvm_profdata_install_hint()
+    ))
+}
+
+/// Where the `llvm-tools-preview` component would install `file_name`, if the
+/// sysroot and default target triple can both be determined. Resolving
+/// either is best-effort: a hiccup here (e.g. `rustc` not on `PATH`) must not
+/// fail the whole lookup when the tool is resolvable on `PATH` instead, so
+/// errors are swallowed into `None` and left to the `PATH` fallback.
+fn sysroot_tool_candidate(file_name: &str) -> Option<PathBuf> {
+    let sysroot = rustc_sysroot().ok()?;
+    let target = crate::build::resolve_default_target().ok()?;
+    Some(
+        sysroot
+            .join("lib")
+            .join("rustlib")
+            .join(target)
+            .join("bin")
+            .join(file_name),
+    )
+}
+
+/// Query `rustc` for the active sysroot.
+fn rustc_sysroot() -> anyhow::Result<PathBuf> {
+    let rustc = std::env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let output = Command::new(rustc).args(["--print", "sysroot"]).output()?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "`rustc --print sysroot` failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    let sysroot = String::from_utf8(output.stdout)?;
+    Ok(PathBuf::from(sysroot.trim()))
+}
+
+/// Look up `file_name` in the directories listed on `PATH`.
+fn search_path(file_name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(file_name))
+        .find(|candidate| candidate.is_file())
+}
+
+/// The platform-specific file name of an executable (adds `.exe` on Windows).
+fn tool_file_name(tool: &str) -> String {
+    format!("{}{}", tool, std::env::consts::EXE_SUFFIX)
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum CargoCommand {
     Build,
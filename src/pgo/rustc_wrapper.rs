@@ -0,0 +1,276 @@
+//! A `RUSTC_WRAPPER` shim that instruments only workspace crates.
+//!
+//! By default cargo-pgo appends the instrumentation flags to a global
+//! `RUSTFLAGS`, which instruments every crate in the dependency graph. In
+//! selective mode it instead sets `RUSTC_WRAPPER` to its own executable, so
+//! Cargo invokes us as `cargo-pgo <real-rustc> <args…>` for every compilation
+//! unit. We forward the invocation untouched and only append the
+//! `-Cprofile-generate`/`-Cprofile-use` flags when the crate being compiled is
+//! a workspace member, leaving registry dependencies, build scripts and
+//! proc-macros uninstrumented. This is the same technique rust-analyzer uses to
+//! avoid recompiling and instrumenting the whole graph.
+
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Command;
+
+/// Holds the instrumentation flags the shim should append to workspace crates.
+pub(crate) const WRAPPER_FLAGS_ENV: &str = "CARGO_PGO_RUSTC_FLAGS";
+/// Holds the workspace root that delimits which crates get instrumented.
+pub(crate) const WRAPPER_WORKSPACE_ROOT_ENV: &str = "CARGO_PGO_WORKSPACE_ROOT";
+
+/// If cargo-pgo was invoked as a `RUSTC_WRAPPER`, run the shim and return the
+/// exit code to propagate; otherwise return `None` so normal CLI dispatch can
+/// proceed.
+///
+/// The flags environment variable alone does not unambiguously mark wrapper
+/// mode: `cargo pgo show-env --instrument` in selective mode exports this
+/// exact variable (see `instrumentation_env`) so a user can `eval` it, and it
+/// then lingers in that interactive shell for every subsequent command. So we
+/// additionally require `argv[1]` — which Cargo always sets to a path to the
+/// real rustc executable when invoking a `RUSTC_WRAPPER` — to resolve to an
+/// existing file; a plain `cargo pgo <subcommand>` has a bare subcommand name
+/// there instead, which never resolves.
+///
+/// Known blind spot: `flags.split_whitespace()` assumes none of the profile
+/// flags contain a space (e.g. a `-Cprofile-generate=<dir>` path under a
+/// directory with a space in its name would be split into two bogus rustc
+/// arguments). The instrumentation flags are always paths cargo-pgo itself
+/// constructs under the target directory, so this only bites if the user's
+/// workspace lives under a path containing whitespace.
+pub(crate) fn run_if_wrapper() -> Option<i32> {
+    let flags = std::env::var(WRAPPER_FLAGS_ENV).ok()?;
+    let workspace_root = std::env::var(WRAPPER_WORKSPACE_ROOT_ENV).unwrap_or_default();
+
+    // Cargo calls us as `$RUSTC_WRAPPER <real-rustc> <args…>`.
+    let mut args = std::env::args_os().skip(1);
+    let rustc = args.next()?;
+    if !Path::new(&rustc).is_file() {
+        // Not actually a rustc invocation (e.g. the flags var leaked into an
+        // interactive shell that then ran a plain `cargo pgo <subcommand>`).
+        return None;
+    }
+    let rustc_args: Vec<OsString> = args.collect();
+
+    let mut command = Command::new(&rustc);
+    command.args(&rustc_args);
+    if should_instrument(&rustc_args, &workspace_root) {
+        for flag in flags.split_whitespace() {
+            command.arg(flag);
+        }
+    }
+
+    Some(exec(command))
+}
+
+/// Decide whether the compilation described by `args` is a workspace member
+/// that should be instrumented. Proc-macros and crates whose source lives under
+/// a registry/vendored path are always skipped so that host-side code stays
+/// uninstrumented.
+///
+/// Known blind spot: on the rare compilation unit whose command line is long
+/// enough that Cargo passes it via an `@argfile` instead of literal
+/// arguments, none of `--crate-name`/`--crate-type`/the source path appear in
+/// `args` and this conservatively returns `false` (uninstrumented) rather
+/// than expanding the file. Workspace crates are small enough in practice
+/// that this should not be hit, but it means a unit compiled this way is
+/// silently left out of the profile.
+fn should_instrument(args: &[OsString], workspace_root: &str) -> bool {
+    let mut crate_types: Vec<String> = Vec::new();
+    let mut crate_name: Option<String> = None;
+    let mut source: Option<&OsString> = None;
+
+    let mut iterator = args.iter();
+    while let Some(arg) = iterator.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "--crate-type" {
+            if let Some(value) = iterator.next() {
+                crate_types.push(value.to_string_lossy().into_owned());
+            }
+        } else if let Some(value) = arg.strip_prefix("--crate-type=") {
+            crate_types.push(value.to_string());
+        } else if arg == "--crate-name" {
+            if let Some(value) = iterator.next() {
+                crate_name = Some(value.to_string_lossy().into_owned());
+            }
+        } else if let Some(value) = arg.strip_prefix("--crate-name=") {
+            crate_name = Some(value.to_string());
+        }
+    }
+    // The input file is the sole positional argument; find the last `.rs` path.
+    for arg in args {
+        let text = arg.to_string_lossy();
+        if !text.starts_with('-') && text.ends_with(".rs") {
+            source = Some(arg);
+        }
+    }
+
+    // Never instrument proc-macros or host build scripts; they run on the build
+    // host and their profiles are worthless for the target binary.
+    if crate_types.iter().any(|kind| kind == "proc-macro") {
+        return false;
+    }
+    if crate_name
+        .as_deref()
+        .is_some_and(|name| name.starts_with("build_script_"))
+    {
+        return false;
+    }
+
+    let Some(source) = source else {
+        return false;
+    };
+    let path = source.to_string_lossy();
+    if path.contains("/registry/") || path.contains("/.cargo/") {
+        return false;
+    }
+
+    // Cargo runs rustc with its CWD set to the package root, so a workspace
+    // member is compiled with a *relative* source path (e.g. `src/main.rs`);
+    // registry/vendored deps use absolute paths and were already filtered out
+    // above. Treat a relative path — or an absolute path that still resolves
+    // under the workspace root — as a workspace member.
+    let source = Path::new(source);
+    if source.is_relative() {
+        return true;
+    }
+    !workspace_root.is_empty() && source.starts_with(workspace_root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::should_instrument;
+    use std::ffi::OsString;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn workspace_member_with_relative_source_is_instrumented() {
+        assert!(should_instrument(
+            &args(&[
+                "--crate-name",
+                "my_crate",
+                "--crate-type",
+                "bin",
+                "src/main.rs"
+            ]),
+            "/home/user/workspace",
+        ));
+    }
+
+    #[test]
+    fn workspace_member_with_absolute_source_under_root_is_instrumented() {
+        assert!(should_instrument(
+            &args(&[
+                "--crate-name",
+                "my_crate",
+                "--crate-type",
+                "lib",
+                "/home/user/workspace/crates/foo/src/lib.rs",
+            ]),
+            "/home/user/workspace",
+        ));
+    }
+
+    #[test]
+    fn registry_dependency_is_not_instrumented() {
+        assert!(!should_instrument(
+            &args(&[
+                "--crate-name",
+                "serde",
+                "--crate-type",
+                "lib",
+                "/home/user/.cargo/registry/src/index.crates.io-abc/serde-1.0.0/src/lib.rs",
+            ]),
+            "/home/user/workspace",
+        ));
+    }
+
+    #[test]
+    fn vendored_cargo_dependency_is_not_instrumented() {
+        assert!(!should_instrument(
+            &args(&[
+                "--crate-name",
+                "libc",
+                "--crate-type",
+                "lib",
+                "/home/user/.cargo/git/checkouts/libc-abc/src/lib.rs",
+            ]),
+            "/home/user/workspace",
+        ));
+    }
+
+    #[test]
+    fn proc_macro_is_not_instrumented() {
+        assert!(!should_instrument(
+            &args(&[
+                "--crate-name",
+                "my_macro",
+                "--crate-type",
+                "proc-macro",
+                "src/lib.rs",
+            ]),
+            "/home/user/workspace",
+        ));
+    }
+
+    #[test]
+    fn build_script_is_not_instrumented() {
+        assert!(!should_instrument(
+            &args(&[
+                "--crate-name",
+                "build_script_build",
+                "--crate-type",
+                "bin",
+                "build.rs",
+            ]),
+            "/home/user/workspace",
+        ));
+    }
+
+    #[test]
+    fn absolute_source_outside_workspace_root_is_not_instrumented() {
+        assert!(!should_instrument(
+            &args(&[
+                "--crate-name",
+                "other_crate",
+                "--crate-type",
+                "lib",
+                "/home/user/other-project/src/lib.rs",
+            ]),
+            "/home/user/workspace",
+        ));
+    }
+
+    #[test]
+    fn missing_source_is_not_instrumented() {
+        assert!(!should_instrument(
+            &args(&["--crate-name", "my_crate", "--crate-type", "bin"]),
+            "/home/user/workspace",
+        ));
+    }
+}
+
+/// Replace the current process with `rustc`, preserving its exact exit code.
+#[cfg(unix)]
+fn exec(mut command: Command) -> i32 {
+    use std::os::unix::process::CommandExt;
+    let error = command.exec();
+    eprintln!("cargo-pgo: failed to exec rustc: {}", error);
+    127
+}
+
+/// On non-Unix platforms we cannot `exec`, so spawn `rustc` and forward its
+/// exit code verbatim.
+#[cfg(not(unix))]
+fn exec(mut command: Command) -> i32 {
+    match command.status() {
+        Ok(status) => status.code().unwrap_or(1),
+        Err(error) => {
+            eprintln!("cargo-pgo: failed to spawn rustc: {}", error);
+            127
+        }
+    }
+}